@@ -53,11 +53,11 @@ extern crate syn;
 extern crate quote;
 
 use std::borrow::Cow;
-use syn::{Body, Field, Ident, MacroInput, VariantData};
+use syn::{Attribute, Body, Field, Ident, MacroInput, MetaItem, NestedMetaItem, VariantData};
 use quote::{Tokens, ToTokens};
 
 /// The type of binding to use when generating a pattern.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum BindStyle {
     /// `x`
     Move,
@@ -69,6 +69,55 @@ pub enum BindStyle {
     RefMut,
 }
 
+impl BindStyle {
+    /// Downgrade this `BindStyle` to a by-value equivalent when binding into a
+    /// `#[repr(packed)]` type, since `ref`/`ref mut` patterns would require
+    /// taking a reference to a potentially-unaligned field. `Move` and
+    /// `MoveMut` are returned unchanged.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use synstructure::BindStyle;
+    ///
+    /// assert_eq!(BindStyle::Ref.with_packed(true), BindStyle::Move);
+    /// assert_eq!(BindStyle::RefMut.with_packed(true), BindStyle::MoveMut);
+    /// assert_eq!(BindStyle::Move.with_packed(true), BindStyle::Move);
+    /// assert_eq!(BindStyle::MoveMut.with_packed(true), BindStyle::MoveMut);
+    ///
+    /// // Unpacked types are never downgraded.
+    /// assert_eq!(BindStyle::Ref.with_packed(false), BindStyle::Ref);
+    /// ```
+    pub fn with_packed(self, is_packed: bool) -> BindStyle {
+        if !is_packed {
+            return self;
+        }
+        match self {
+            BindStyle::Ref => BindStyle::Move,
+            BindStyle::RefMut => BindStyle::MoveMut,
+            style => style,
+        }
+    }
+}
+
+/// Returns true if `attrs` contains a `#[repr(packed)]` or `#[repr(..., packed, ...)]` attribute.
+fn is_packed(attrs: &[Attribute]) -> bool {
+    attrs.iter().any(|attr| {
+        if let MetaItem::List(ref ident, ref items) = attr.value {
+            ident == "repr" &&
+                items.iter().any(|item| {
+                    if let NestedMetaItem::MetaItem(MetaItem::Word(ref ident)) = *item {
+                        ident == "packed"
+                    } else {
+                        false
+                    }
+                })
+        } else {
+            false
+        }
+    })
+}
+
 impl ToTokens for BindStyle {
     fn to_tokens(&self, tokens: &mut Tokens) {
         match *self {
@@ -91,10 +140,16 @@ impl ToTokens for BindStyle {
 ///
 /// `prefix` controls the name which is used for the binding. This can be used
 /// to avoid name conflicts with nested match patterns.
+///
+/// `is_packed` is resolved once per `MacroInput` by `match_substructs`, and
+/// causes `match_pattern` to downgrade `bind_style` (see
+/// `BindStyle::with_packed`) so that `#[repr(packed)]` types never get
+/// `ref`/`ref mut` patterns.
 #[derive(Debug, Clone)]
 pub struct BindOpts {
     bind_style: BindStyle,
     prefix: Cow<'static, str>,
+    is_packed: bool,
 }
 
 impl BindOpts {
@@ -103,6 +158,7 @@ impl BindOpts {
         BindOpts {
             bind_style: bind_style,
             prefix: "__binding".into(),
+            is_packed: false,
         }
     }
 
@@ -111,6 +167,7 @@ impl BindOpts {
         BindOpts {
             bind_style: bind_style,
             prefix: prefix.into(),
+            is_packed: false,
         }
     }
 }
@@ -125,24 +182,128 @@ impl From<BindStyle> for BindOpts {
 /// reference to the given field, and the syn `&'a Field` descriptor for that
 /// field.
 ///
+/// `expr` is the expression to use to *read* the field's value. Today this is
+/// always equal to `ident` -- alignment safety on `#[repr(packed)]` types is
+/// already handled by `match_pattern` downgrading the bind style (see
+/// `BindStyle::with_packed`), so no extra copy is needed. `expr` exists as a
+/// separate field so that a future derive which needs a different read
+/// expression than the bare binding name has somewhere to put it, without
+/// another signature change.
+///
 /// This type supports `quote::ToTokens`, so can be directly used within the
-/// `quote!` macro. It expands to a reference to the matched field.
+/// `quote!` macro. It expands to `expr`.
 #[derive(Debug)]
 pub struct BindingInfo<'a> {
     pub ident: Ident,
+    pub expr: Tokens,
     pub field: &'a mut Field,
 }
 
 impl<'a> ToTokens for BindingInfo<'a> {
     fn to_tokens(&self, tokens: &mut Tokens) {
-        self.ident.to_tokens(tokens);
+        self.expr.to_tokens(tokens);
     }
 }
 
+impl<'a> BindingInfo<'a> {
+    /// Removes and returns all of this field's attributes named `name`, e.g.
+    /// `#[name(..)]`. This lets a derive consume per-field configuration
+    /// attributes as it interprets them, so that any attributes which are
+    /// left over afterwards can be reported as unknown.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// extern crate syn;
+    /// extern crate synstructure;
+    /// #[macro_use]
+    /// extern crate quote;
+    /// use synstructure::{match_substructs, BindStyle};
+    ///
+    /// fn main() {
+    ///     let mut ast = syn::parse_macro_input(
+    ///         "struct A { #[foo(skip)] a: i32, #[bar] b: i32 }"
+    ///     ).unwrap();
+    ///
+    ///     match_substructs(&mut ast, &BindStyle::Ref.into(), |_info, mut bindings| {
+    ///         assert!(bindings[0].has_attr("foo"));
+    ///         assert!(!bindings[0].has_attr("bar"));
+    ///
+    ///         let removed = bindings[0].filter_attrs("foo");
+    ///         assert_eq!(removed.len(), 1);
+    ///         assert!(!bindings[0].has_attr("foo"));
+    ///
+    ///         // Attributes on other fields are left alone.
+    ///         assert!(bindings[1].has_attr("bar"));
+    ///
+    ///         quote!(())
+    ///     });
+    ///
+    ///     // The consumed attribute was actually removed from the underlying
+    ///     // `syn::Field`, not just hidden from view.
+    ///     if let syn::Body::Struct(syn::VariantData::Struct(ref fields)) = ast.body {
+    ///         assert!(fields[0].attrs.is_empty());
+    ///         assert_eq!(fields[1].attrs.len(), 1);
+    ///     } else {
+    ///         unreachable!()
+    ///     }
+    /// }
+    /// ```
+    pub fn filter_attrs(&mut self, name: &str) -> Vec<Attribute> {
+        let mut filtered = Vec::new();
+        let mut i = 0;
+        while i < self.field.attrs.len() {
+            if self.field.attrs[i].value.name() == name {
+                filtered.push(self.field.attrs.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        filtered
+    }
+
+    /// Returns true if this field has an attribute named `name`, without
+    /// consuming it.
+    pub fn has_attr(&self, name: &str) -> bool {
+        self.field.attrs.iter().any(|attr| attr.value.name() == name)
+    }
+}
+
+/// The shape of a `syn::VariantData`, as distinguished by `match_pattern`.
+/// This lets a `match_substructs` callback tell which kind
+/// of pattern it is generating a body for without re-matching on the
+/// original `VariantData` itself.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Style {
+    /// `Variant`
+    Unit,
+    /// `Variant(a, b)`
+    Tuple,
+    /// `Variant { a: .., b: .. }`
+    Struct,
+}
+
+/// Context identifying which substructure a `match_substructs` callback is
+/// currently generating a match arm for.
+///
+/// `path` is the full constructor path which was matched against (e.g.
+/// `Enum::Variant`, or just `Struct` for a plain struct). `ident` is the bare
+/// variant identifier (or the struct's own identifier, when there are no
+/// variants). `attrs` are the `syn::Attribute`s attached to that variant (or,
+/// for a plain struct, to the struct item itself).
+#[derive(Debug)]
+pub struct VariantInfo<'a> {
+    pub path: Tokens,
+    pub ident: &'a Ident,
+    pub style: Style,
+    pub attrs: &'a [Attribute],
+}
+
 /// Generate a match pattern for binding to the given VariantData This function
-/// returns a tuple of the tokens which make up that match pattern, and a
-/// `BindingInfo` object for each of the bindings which were made. The `bind`
-/// parameter controls the type of binding which is made.
+/// returns a tuple of the tokens which make up that match pattern, the
+/// `Style` of the `VariantData` which was matched, and a `BindingInfo` object
+/// for each of the bindings which were made. The `options` parameter controls
+/// the type of binding which is made.
 ///
 /// The `BindingInfo` object holds a mutable reference into the original
 /// `VariantData`, which means that mutations will be reflected in the source
@@ -163,10 +324,11 @@ impl<'a> ToTokens for BindingInfo<'a> {
 ///         vd
 ///     } else { unreachable!() };
 ///
-///     let (tokens, bindings) = match_pattern(&ast.ident, vd, &BindStyle::Ref.into());
+///     let (tokens, style, bindings) = match_pattern(&ast.ident, vd, &BindStyle::Ref.into());
 ///     assert_eq!(tokens.to_string(), quote! {
 ///          A{ a: ref __binding_0, b: ref __binding_1, }
 ///     }.to_string());
+///     assert_eq!(style, synstructure::Style::Struct);
 ///     assert_eq!(bindings.len(), 2);
 ///     assert_eq!(&bindings[0].ident.to_string(), "__binding_0");
 ///     assert_eq!(&bindings[1].ident.to_string(), "__binding_1");
@@ -175,12 +337,18 @@ impl<'a> ToTokens for BindingInfo<'a> {
 pub fn match_pattern<'a, N: ToTokens>(name: &N,
                                       vd: &'a mut VariantData,
                                       options: &BindOpts)
-                                      -> (Tokens, Vec<BindingInfo<'a>>) {
+                                      -> (Tokens, Style, Vec<BindingInfo<'a>>) {
     let mut t = Tokens::new();
     let mut matches = Vec::new();
 
-    let binding = options.bind_style;
+    let binding = options.bind_style.with_packed(options.is_packed);
+
     name.to_tokens(&mut t);
+    let style = match *vd {
+        VariantData::Unit => Style::Unit,
+        VariantData::Tuple(_) => Style::Tuple,
+        VariantData::Struct(_) => Style::Struct,
+    };
     match *vd {
         VariantData::Unit => {}
         VariantData::Tuple(ref mut fields) => {
@@ -188,8 +356,10 @@ pub fn match_pattern<'a, N: ToTokens>(name: &N,
             for (i, field) in fields.iter_mut().enumerate() {
                 let ident: Ident = format!("{}_{}", options.prefix, i).into();
                 quote!(#binding #ident ,).to_tokens(&mut t);
+                let expr = quote!(#ident);
                 matches.push(BindingInfo {
                     ident: ident,
+                    expr: expr,
                     field: field,
                 });
             }
@@ -203,8 +373,10 @@ pub fn match_pattern<'a, N: ToTokens>(name: &N,
                     let field_name = field.ident.as_ref().unwrap();
                     quote!(#field_name : #binding #ident ,).to_tokens(&mut t);
                 }
+                let expr = quote!(#ident);
                 matches.push(BindingInfo {
                     ident: ident,
+                    expr: expr,
                     field: field,
                 });
             }
@@ -212,14 +384,21 @@ pub fn match_pattern<'a, N: ToTokens>(name: &N,
         }
     }
 
-    (t, matches)
+    (t, style, matches)
 }
 
 /// This method generates a match branch for each of the substructures of the
 /// given `MacroInput`. It will call `func` for each of these substructures,
-/// passing in the bindings which were made for each of the fields in the
+/// passing in a `VariantInfo` describing the arm being generated (its
+/// constructor path, variant identifier, `Style` and attributes) together
+/// with the bindings which were made for each of the fields in the
 /// substructure. The return value of `func` is then used as the value of each
-/// branch
+/// branch.
+///
+/// This is the richer entry point: it lets a derive special-case variants
+/// (e.g. to emit the variant name or discriminant) or honor variant-level
+/// attributes. Derives which don't need this context can use `each_field`
+/// instead.
 ///
 /// The `BindingInfo` object holds a mutable reference into the original
 /// `MacroInput`, which means that mutations will be reflected in the source
@@ -237,7 +416,8 @@ pub fn match_pattern<'a, N: ToTokens>(name: &N,
 /// fn main() {
 ///     let mut ast = syn::parse_macro_input("struct A { a: i32, b: i32 }").unwrap();
 ///
-///     let tokens = match_substructs(&mut ast, &BindStyle::Ref.into(), |bindings| {
+///     let tokens = match_substructs(&mut ast, &BindStyle::Ref.into(), |info, bindings| {
+///         assert_eq!(info.ident.as_ref(), "A");
 ///         assert_eq!(bindings.len(), 2);
 ///         assert_eq!(bindings[0].ident.as_ref(), "__binding_0");
 ///         assert_eq!(bindings[1].ident.as_ref(), "__binding_1");
@@ -248,33 +428,120 @@ pub fn match_pattern<'a, N: ToTokens>(name: &N,
 ///     }.to_string());
 /// }
 /// ```
+///
+/// # `#[repr(packed)]` Example
+///
+/// Even though `BindStyle::Ref` is requested, a `#[repr(packed)]` type gets
+/// by-value bindings instead, since a `ref` pattern would take a reference to
+/// a potentially-unaligned field.
+///
+/// ```
+/// extern crate syn;
+/// extern crate synstructure;
+/// #[macro_use]
+/// extern crate quote;
+/// use synstructure::{match_substructs, BindStyle};
+///
+/// fn main() {
+///     let mut ast = syn::parse_macro_input("#[repr(packed)] struct A { a: i32, b: i32 }").unwrap();
+///
+///     let tokens = match_substructs(&mut ast, &BindStyle::Ref.into(), |_info, _bindings| {
+///         quote!("some_random_string")
+///     });
+///     assert_eq!(tokens.to_string(), quote! {
+///         A { a: __binding_0, b: __binding_1, } => { "some_random_string" }
+///     }.to_string());
+/// }
+/// ```
+///
+/// # Enum Example
+///
+/// This shows the `VariantInfo` passed to `func` for each arm of an enum:
+/// the full constructor path, the bare variant identifier, its `Style`, and
+/// its attributes (e.g. so a derive can special-case a `#[my_attr]`-marked
+/// variant).
+///
+/// ```
+/// extern crate syn;
+/// extern crate synstructure;
+/// #[macro_use]
+/// extern crate quote;
+/// use synstructure::{match_substructs, BindStyle, Style};
+///
+/// fn main() {
+///     let mut ast = syn::parse_macro_input(
+///         "enum E { #[my_attr] A(i32), B { x: i32 } }"
+///     ).unwrap();
+///
+///     let tokens = match_substructs(&mut ast, &BindStyle::Ref.into(), |info, bindings| {
+///         assert_eq!(info.path.to_string(), format!("E :: {}", info.ident));
+///         assert_eq!(bindings.len(), 1);
+///         if info.ident.as_ref() == "A" {
+///             assert_eq!(info.style, Style::Tuple);
+///             assert!(info.attrs.iter().any(|a| a.value.name() == "my_attr"));
+///         } else {
+///             assert_eq!(info.ident.as_ref(), "B");
+///             assert_eq!(info.style, Style::Struct);
+///             assert!(info.attrs.is_empty());
+///         }
+///         quote!(())
+///     });
+///     assert_eq!(tokens.to_string(), quote! {
+///         E :: A ( ref __binding_0 , ) => { () }
+///         E :: B { x : ref __binding_0 , } => { () }
+///     }.to_string());
+/// }
+/// ```
 pub fn match_substructs<F, T: ToTokens>(input: &mut MacroInput,
                                         options: &BindOpts,
                                         func: F)
                                         -> Tokens
-    where F: Fn(Vec<BindingInfo>) -> T
+    where F: Fn(VariantInfo, Vec<BindingInfo>) -> T
 {
     let ident = &input.ident;
+    let attrs = &input.attrs;
+
+    // Resolve `#[repr(packed)]` once for this `MacroInput`, and downgrade the
+    // bind style accordingly so that every substructure gets safe patterns.
+    let mut options = options.clone();
+    options.is_packed = is_packed(&input.attrs);
+    let options = &options;
+
     // Generate patterns for matching against all of the variants
     let variants = match input.body {
         Body::Enum(ref mut variants) => {
             variants.iter_mut()
                 .map(|variant| {
                     let variant_ident = &variant.ident;
-                    match_pattern(&quote!(#ident :: #variant_ident),
-                                  &mut variant.data,
-                                  options)
+                    let path = quote!(#ident :: #variant_ident);
+                    let (pat, style, bindings) = match_pattern(&path, &mut variant.data, options);
+                    let info = VariantInfo {
+                        path: path,
+                        ident: variant_ident,
+                        style: style,
+                        attrs: &variant.attrs,
+                    };
+                    (pat, info, bindings)
                 })
                 .collect()
         }
-        Body::Struct(ref mut vd) => vec![match_pattern(&ident, vd, options)],
+        Body::Struct(ref mut vd) => {
+            let (pat, style, bindings) = match_pattern(&ident, vd, options);
+            let info = VariantInfo {
+                path: quote!(#ident),
+                ident: ident,
+                style: style,
+                attrs: attrs,
+            };
+            vec![(pat, info, bindings)]
+        }
     };
 
     // Now that we have the patterns, generate the actual branches of the match
     // expression
     let mut t = Tokens::new();
-    for (pat, bindings) in variants {
-        let body = func(bindings);
+    for (pat, info, bindings) in variants {
+        let body = func(info, bindings);
         quote!(#pat => { #body }).to_tokens(&mut t);
     }
 
@@ -316,14 +583,60 @@ pub fn match_substructs<F, T: ToTokens>(input: &mut MacroInput,
 pub fn each_field<F, T: ToTokens>(input: &mut MacroInput, options: &BindOpts, func: F) -> Tokens
     where F: Fn(BindingInfo) -> T
 {
-    match_substructs(input, options, |infos| {
+    match_substructs(input, options, |_variant, bindings| {
         let mut t = Tokens::new();
-        for info in infos {
+        for bi in bindings {
             t.append("{");
-            func(info).to_tokens(&mut t);
+            func(bi).to_tokens(&mut t);
             t.append("}");
         }
         quote!(()).to_tokens(&mut t);
         t
     })
 }
+
+/// This method left-folds `func` over the bindings of each substructure of
+/// the given `MacroInput`, starting from `init`, and makes the resulting
+/// expression the body of each match arm. Unlike `each_field`, which always
+/// produces a series of statements followed by `()`, this builds a single
+/// expression out of all of a substructure's bindings, which is what's
+/// needed to combine fields into a value, such as a combined hash, an
+/// equality `&&` chain, or an ordering comparison.
+///
+/// The `BindingInfo` object holds a mutable reference into the original
+/// `MacroInput`, which means that mutations will be reflected in the source
+/// object. This can be useful for removing attributes as they are used.
+///
+/// # Example
+///
+/// ```
+/// extern crate syn;
+/// extern crate synstructure;
+/// #[macro_use]
+/// extern crate quote;
+/// use synstructure::{fold_fields, BindStyle};
+///
+/// fn main() {
+///     let mut ast = syn::parse_macro_input("struct A { a: i32, b: i32 }").unwrap();
+///
+///     let tokens = fold_fields(&mut ast, &BindStyle::Ref.into(), quote!(0i64), |acc, bi| quote! {
+///         #acc + (#bi as i64)
+///     });
+///     assert_eq!(tokens.to_string(), quote! {
+///         A{ a: ref __binding_0, b: ref __binding_1, } => {
+///             0i64 + (__binding_0 as i64) + (__binding_1 as i64)
+///         }
+///     }.to_string());
+/// }
+/// ```
+pub fn fold_fields<F>(input: &mut MacroInput, options: &BindOpts, init: Tokens, func: F) -> Tokens
+    where F: Fn(Tokens, BindingInfo) -> Tokens
+{
+    match_substructs(input, options, |_variant, bindings| {
+        let mut acc = init.clone();
+        for bi in bindings {
+            acc = func(acc, bi);
+        }
+        acc
+    })
+}